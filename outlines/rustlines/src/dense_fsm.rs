@@ -0,0 +1,104 @@
+//! A dense, cache-friendly transition table compacted from the sparse
+//! `BTreeMap<(i32, i32), i32>` FSM representation.
+//!
+//! `_walk_fsm` does a `BTreeMap` lookup per input symbol on the hottest
+//! inner loop, which is a handful of pointer-chasing comparisons per step.
+//! `DenseFsm` instead packs transitions into a flat `Vec<i32>` of shape
+//! `n_states * n_symbols`, with `MISSING` marking an absent transition, plus
+//! a parallel final-state bitset, so a walk step is an O(1) array index and
+//! a bit test. Built once per FSM and reused across every `start_state`
+//! scan.
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PySet};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const MISSING: i32 = -1;
+
+#[pyclass]
+#[derive(Clone)]
+pub struct DenseFsm {
+    transitions: Vec<i32>,
+    finals: Vec<u64>,
+    n_states: usize,
+    n_symbols: usize,
+}
+
+impl DenseFsm {
+    pub fn build(fsm_transitions: &BTreeMap<(i32, i32), i32>, fsm_finals: &BTreeSet<i32>) -> Self {
+        let n_states = fsm_transitions
+            .iter()
+            .flat_map(|(&(state, _), &next_state)| [state, next_state])
+            .chain(fsm_finals.iter().copied())
+            .max()
+            .map(|max_state| max_state as usize + 1)
+            .unwrap_or(0);
+        let n_symbols = fsm_transitions
+            .keys()
+            .map(|&(_, symbol)| symbol)
+            .max()
+            .map(|max_symbol| max_symbol as usize + 1)
+            .unwrap_or(0);
+
+        let mut transitions = vec![MISSING; n_states * n_symbols];
+        for (&(state, symbol), &next_state) in fsm_transitions {
+            transitions[state as usize * n_symbols + symbol as usize] = next_state;
+        }
+
+        let mut finals = vec![0u64; n_states / 64 + 1];
+        for &state in fsm_finals {
+            finals[state as usize / 64] |= 1 << (state as usize % 64);
+        }
+
+        DenseFsm {
+            transitions,
+            finals,
+            n_states,
+            n_symbols,
+        }
+    }
+
+    #[inline]
+    pub fn next_state(&self, state: i32, symbol: i32) -> Option<i32> {
+        if state < 0 || symbol < 0 {
+            return None;
+        }
+        let (state, symbol) = (state as usize, symbol as usize);
+        if state >= self.n_states || symbol >= self.n_symbols {
+            return None;
+        }
+        match self.transitions[state * self.n_symbols + symbol] {
+            MISSING => None,
+            next_state => Some(next_state),
+        }
+    }
+
+    #[inline]
+    pub fn is_final(&self, state: i32) -> bool {
+        if state < 0 || state as usize >= self.n_states {
+            return false;
+        }
+        let state = state as usize;
+        (self.finals[state / 64] >> (state % 64)) & 1 == 1
+    }
+}
+
+#[pymethods]
+impl DenseFsm {
+    #[new]
+    fn new(fsm_transitions: &PyDict, fsm_finals: &PySet) -> PyResult<Self> {
+        let fsm_transitions_map = fsm_transitions
+            .iter()
+            .map(|(k, v)| Ok((k.extract::<(i32, i32)>()?, v.extract::<i32>()?)))
+            .collect::<PyResult<BTreeMap<(i32, i32), i32>>>()?;
+        let fsm_finals_set = fsm_finals
+            .iter()
+            .map(|v| v.extract::<i32>())
+            .collect::<PyResult<BTreeSet<i32>>>()?;
+        Ok(DenseFsm::build(&fsm_transitions_map, &fsm_finals_set))
+    }
+
+    fn __len__(&self) -> usize {
+        self.n_states
+    }
+}
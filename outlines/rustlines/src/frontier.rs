@@ -0,0 +1,197 @@
+//! Shared worker-pool frontier BFS driver used by every
+//! `create_fsm_index_end_to_end_rust*` entry point.
+//!
+//! Workers pull a `start_state` off a shared crossbeam queue, run
+//! `scan_state` for it, and race to enqueue any newly-discovered
+//! `end_state` under a shared `seen` set so each state is scanned exactly
+//! once. An `AtomicUsize` counts states that are queued-or-in-flight; once
+//! it drops to zero there is no more work and every worker exits.
+//!
+//! Progress reporting and cooperative cancellation are optional knobs via
+//! `FrontierOptions` so callers that don't need them (the `_compiled` and
+//! `_dense` fast paths) aren't forced to duplicate the driver just to leave
+//! them out.
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of workers draining the state-frontier queue. Each worker still
+/// fans its own per-state vocabulary scan out internally (see
+/// `_state_scan_tokens` and friends), so the effective thread count is
+/// `N_FRONTIER_WORKERS * (threads used per scan)` — callers of
+/// `run_frontier_bfs` should size their own per-scan fan-out with that in
+/// mind rather than assuming they own the whole machine.
+pub const N_FRONTIER_WORKERS: usize = 8;
+
+/// How often the reporter thread invokes the Python progress callback.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A cooperative cancellation flag for long-running index builds. Python
+/// code can hold onto one of these and call `cancel()` from another thread
+/// (e.g. a signal handler) to abort an in-flight `create_fsm_index_end_to_end_rust`.
+#[pyclass]
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    pub cancelled: Arc<AtomicBool>,
+}
+
+#[pymethods]
+impl CancellationToken {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A snapshot of build progress, sent roughly once per state a worker
+/// finishes scanning.
+#[derive(Clone, Copy, Default)]
+pub struct BuildProgress {
+    pub states_seen: usize,
+    pub frontier_remaining: usize,
+    pub tokens_scanned: usize,
+    pub elapsed_ms: u128,
+}
+
+impl BuildProgress {
+    pub fn to_py_dict(self, py: Python) -> PyResult<Py<PyDict>> {
+        let dict = PyDict::new(py);
+        dict.set_item("states_seen", self.states_seen)?;
+        dict.set_item("frontier_remaining", self.frontier_remaining)?;
+        dict.set_item("tokens_scanned", self.tokens_scanned)?;
+        dict.set_item("elapsed_ms", self.elapsed_ms)?;
+        Ok(dict.into())
+    }
+}
+
+/// Optional cooperative-cancellation/progress-reporting knobs for
+/// `run_frontier_bfs`. `Default::default()` opts out of both.
+#[derive(Clone, Default)]
+pub struct FrontierOptions {
+    pub cancelled: Option<Arc<AtomicBool>>,
+    pub progress_tx: Option<Sender<BuildProgress>>,
+    /// Vocabulary size `scan_state` attempts per call, i.e. `BuildProgress`'s
+    /// `tokens_scanned` counts scan *attempts* (matching the pre-refactor
+    /// behavior of adding the full vocabulary size per processed state), not
+    /// the (much smaller) count of matched `(token_id, end_state)` pairs
+    /// `scan_state` returns. Ignored unless `progress_tx` is set.
+    pub tokens_per_state: usize,
+}
+
+/// Explore the reachable-state frontier of an FSM with a worker pool:
+/// `scan_state(start_state)` performs the full-vocabulary scan for one
+/// state and is called concurrently across up to `N_FRONTIER_WORKERS`
+/// workers. Returns the same `BTreeMap<i32, BTreeSet<(i32, i32)>>` shape
+/// regardless of which `scan_state` implementation (sparse map, compiled
+/// vocabulary, dense table, ...) is plugged in.
+pub fn run_frontier_bfs<F>(
+    fsm_initial: i32,
+    options: FrontierOptions,
+    scan_state: F,
+) -> BTreeMap<i32, BTreeSet<(i32, i32)>>
+where
+    F: Fn(i32) -> Vec<(i32, i32)> + Sync,
+{
+    let (frontier_tx, frontier_rx): (Sender<i32>, Receiver<i32>) = unbounded();
+    let seen: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+    seen.lock().expect("seen set poisoned").insert(fsm_initial);
+    let pending = AtomicUsize::new(1);
+    let tokens_scanned = AtomicUsize::new(0);
+    frontier_tx
+        .send(fsm_initial)
+        .expect("frontier channel open");
+    let build_start = Instant::now();
+
+    let per_worker_results: Vec<Vec<(i32, BTreeMap<i32, i32>)>> = thread::scope(|s| {
+        (0..N_FRONTIER_WORKERS)
+            .map(|_| {
+                let frontier_rx = frontier_rx.clone();
+                let frontier_tx = frontier_tx.clone();
+                let seen = &seen;
+                let pending = &pending;
+                let tokens_scanned = &tokens_scanned;
+                let cancelled = options.cancelled.clone();
+                let progress_tx = options.progress_tx.clone();
+                let tokens_per_state = options.tokens_per_state;
+                let scan_state = &scan_state;
+
+                s.spawn(move || {
+                    let mut results = Vec::new();
+                    loop {
+                        if let Some(cancelled) = &cancelled {
+                            if cancelled.load(Ordering::SeqCst) {
+                                break;
+                            }
+                        }
+                        let start_state = match frontier_rx.recv_timeout(Duration::from_millis(50))
+                        {
+                            Ok(start_state) => start_state,
+                            Err(_) if pending.load(Ordering::SeqCst) == 0 => break,
+                            Err(_) => continue,
+                        };
+
+                        let token_ids_end_states = scan_state(start_state);
+
+                        let mut token_subsets: BTreeMap<i32, i32> = BTreeMap::new();
+                        for (token_id, end_state) in token_ids_end_states {
+                            let newly_discovered =
+                                seen.lock().expect("seen set poisoned").insert(end_state);
+                            if newly_discovered {
+                                pending.fetch_add(1, Ordering::SeqCst);
+                                frontier_tx.send(end_state).expect("frontier channel open");
+                            }
+                            token_subsets.insert(token_id, end_state);
+                        }
+                        let tokens_scanned_total =
+                            tokens_scanned.fetch_add(tokens_per_state, Ordering::SeqCst)
+                                + tokens_per_state;
+                        results.push((start_state, token_subsets));
+                        pending.fetch_sub(1, Ordering::SeqCst);
+
+                        if let Some(progress_tx) = &progress_tx {
+                            let _ = progress_tx.send(BuildProgress {
+                                states_seen: seen.lock().expect("seen set poisoned").len(),
+                                frontier_remaining: frontier_rx.len(),
+                                tokens_scanned: tokens_scanned_total,
+                                elapsed_ms: build_start.elapsed().as_millis(),
+                            });
+                        }
+                    }
+                    results
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().expect("frontier worker panicked"))
+            .collect()
+    });
+    drop(frontier_tx);
+    drop(options.progress_tx);
+
+    let mut states_to_token_subsets_btree: BTreeMap<i32, BTreeSet<(i32, i32)>> = BTreeMap::new();
+    for worker_results in per_worker_results {
+        for (start_state, token_subsets) in worker_results {
+            let entry = states_to_token_subsets_btree
+                .entry(start_state)
+                .or_default();
+            for (token_id, end_state) in token_subsets {
+                entry.insert((token_id, end_state));
+            }
+        }
+    }
+    states_to_token_subsets_btree
+}
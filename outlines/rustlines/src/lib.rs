@@ -1,10 +1,51 @@
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use pyo3::exceptions::PyKeyboardInterrupt;
 use pyo3::prelude::*;
 use pyo3::types::{PyDict, PySet};
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 
+mod mmap_index;
+use mmap_index::{open_index, write_index, MmapIndex};
+
+mod vocab;
+use vocab::CompiledVocabulary;
+
+mod report;
+use report::{Report, ReportMode};
+
+mod dense_fsm;
+use dense_fsm::DenseFsm;
+
+mod frontier;
+use frontier::{
+    run_frontier_bfs, BuildProgress, CancellationToken, FrontierOptions, N_FRONTIER_WORKERS,
+    PROGRESS_INTERVAL,
+};
+
+/// Threads used by a single `_state_scan_tokens*` call made directly (e.g.
+/// from the standalone `state_scan_tokens` pyfunction, not via
+/// `run_frontier_bfs`).
+const STANDALONE_SCAN_THREADS: usize = 16;
+
+/// Threads used by a `_state_scan_tokens*` call made from inside one of
+/// `run_frontier_bfs`'s own `N_FRONTIER_WORKERS` workers. Those workers
+/// already run concurrently, so handing each one `STANDALONE_SCAN_THREADS`
+/// more would oversubscribe the machine `N_FRONTIER_WORKERS`x; split the
+/// same total budget between them instead, floored at 1 so a future bump to
+/// `N_FRONTIER_WORKERS` past `STANDALONE_SCAN_THREADS` can't divide it to 0.
+const FRONTIER_SCAN_THREADS: usize = {
+    let threads = STANDALONE_SCAN_THREADS / N_FRONTIER_WORKERS;
+    if threads == 0 {
+        1
+    } else {
+        threads
+    }
+};
+
 fn _walk_fsm(
     fsm_transitions: &BTreeMap<(i32, i32), i32>,
     alphabet_symbol_mapping: &BTreeMap<char, i32>,
@@ -45,6 +86,166 @@ fn _walk_fsm(
     accepted_states
 }
 
+/// Same walk as `_walk_fsm`, but over a precomputed symbol-id sequence (see
+/// `CompiledVocabulary`) instead of a `&str`, so the hot loop does a single
+/// integer lookup per symbol with no per-character hashmap resolution or
+/// UTF-8 re-decoding.
+fn _walk_fsm_symbols(
+    fsm_transitions: &BTreeMap<(i32, i32), i32>,
+    fsm_finals: &BTreeSet<i32>,
+    symbols: &[i32],
+    start_state: i32,
+    full_match: bool,
+) -> Vec<i32> {
+    let mut state = start_state;
+    let mut accepted_states = Vec::new();
+    let mut is_final_state_reached = false;
+
+    for &symbol in symbols {
+        if let Some(&new_state) = fsm_transitions.get(&(state, symbol)) {
+            state = new_state;
+            if fsm_finals.contains(&state) {
+                is_final_state_reached = true;
+            }
+            accepted_states.push(state);
+        } else {
+            if !full_match && is_final_state_reached {
+                break;
+            }
+            return Vec::new();
+        }
+    }
+
+    if full_match && !is_final_state_reached {
+        return Vec::new();
+    }
+
+    accepted_states
+}
+
+/// `_state_scan_tokens` over a `CompiledVocabulary` instead of a raw
+/// `vocabulary: &PyDict`: tokens are already interned into symbol-id
+/// sequences, so chunks are scanned with `_walk_fsm_symbols` directly.
+fn _state_scan_tokens_compiled(
+    fsm_transitions_map: &BTreeMap<(i32, i32), i32>,
+    fsm_finals_set: &BTreeSet<i32>,
+    compiled_vocabulary: &CompiledVocabulary,
+    start_state: i32,
+    max_threads: usize,
+) -> Vec<(i32, i32)> {
+    let n_tokens = compiled_vocabulary.tokens.len();
+    let n_threads = if n_tokens > 1000 { max_threads } else { 1 };
+    let tokens_per_thread = (n_tokens as f32 / n_threads as f32).ceil() as usize;
+
+    thread::scope(|s| {
+        (0..n_threads)
+            .filter_map(|thread_id| {
+                let start = thread_id * tokens_per_thread;
+                let end = (start + tokens_per_thread).min(n_tokens);
+                if start >= end {
+                    return None;
+                }
+                let chunk = &compiled_vocabulary.tokens[start..end];
+                Some(s.spawn(move || {
+                    let mut res = Vec::new();
+                    for token in chunk {
+                        let state_seq = _walk_fsm_symbols(
+                            fsm_transitions_map,
+                            fsm_finals_set,
+                            &token.symbols,
+                            start_state,
+                            false,
+                        );
+                        if state_seq.len() < token.symbols.len() {
+                            continue;
+                        }
+                        for token_id in &token.token_ids {
+                            res.push((*token_id, state_seq[state_seq.len() - 1]));
+                        }
+                    }
+                    res
+                }))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("thread failed"))
+            .collect()
+    })
+}
+
+/// Same walk as `_walk_fsm_symbols`, but indexing a `DenseFsm` instead of the
+/// sparse `BTreeMap<(i32, i32), i32>`: each step is an O(1) array index and
+/// a bit test rather than a tree descent.
+fn _walk_fsm_dense(dense_fsm: &DenseFsm, symbols: &[i32], start_state: i32, full_match: bool) -> Vec<i32> {
+    let mut state = start_state;
+    let mut accepted_states = Vec::new();
+    let mut is_final_state_reached = false;
+
+    for &symbol in symbols {
+        if let Some(new_state) = dense_fsm.next_state(state, symbol) {
+            state = new_state;
+            if dense_fsm.is_final(state) {
+                is_final_state_reached = true;
+            }
+            accepted_states.push(state);
+        } else {
+            if !full_match && is_final_state_reached {
+                break;
+            }
+            return Vec::new();
+        }
+    }
+
+    if full_match && !is_final_state_reached {
+        return Vec::new();
+    }
+
+    accepted_states
+}
+
+/// `_state_scan_tokens_compiled` over a `DenseFsm` instead of the sparse
+/// transition map.
+fn _state_scan_tokens_dense(
+    dense_fsm: &DenseFsm,
+    compiled_vocabulary: &CompiledVocabulary,
+    start_state: i32,
+    max_threads: usize,
+) -> Vec<(i32, i32)> {
+    let n_tokens = compiled_vocabulary.tokens.len();
+    let n_threads = if n_tokens > 1000 { max_threads } else { 1 };
+    let tokens_per_thread = (n_tokens as f32 / n_threads as f32).ceil() as usize;
+
+    thread::scope(|s| {
+        (0..n_threads)
+            .filter_map(|thread_id| {
+                let start = thread_id * tokens_per_thread;
+                let end = (start + tokens_per_thread).min(n_tokens);
+                if start >= end {
+                    return None;
+                }
+                let chunk = &compiled_vocabulary.tokens[start..end];
+                Some(s.spawn(move || {
+                    let mut res = Vec::new();
+                    for token in chunk {
+                        let state_seq =
+                            _walk_fsm_dense(dense_fsm, &token.symbols, start_state, false);
+                        if state_seq.len() < token.symbols.len() {
+                            continue;
+                        }
+                        for token_id in &token.token_ids {
+                            res.push((*token_id, state_seq[state_seq.len() - 1]));
+                        }
+                    }
+                    res
+                }))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("thread failed"))
+            .collect()
+    })
+}
+
 fn _state_scan_tokens(
     fsm_transitions_map: &BTreeMap<(i32, i32), i32>,
     alphabet_symbol_mapping_map: &BTreeMap<char, i32>,
@@ -53,9 +254,10 @@ fn _state_scan_tokens(
     fsm_finals_set: &BTreeSet<i32>,
     vocabulary_map: &BTreeMap<String, Vec<i32>>,
     start_state: i32,
+    max_threads: usize,
 ) -> PyResult<Vec<(i32, i32)>> {
     let _start_time = std::time::Instant::now();
-    let mut n_threads = 16;
+    let mut n_threads = max_threads;
 
     // Convert fsm_transitions to BTreeMap and two vectors
     let mut tokens = Vec::new();
@@ -131,7 +333,7 @@ fn _state_scan_tokens(
                             start_state,
                             false,
                         );
-                        if state_seq.len() < token.len() {
+                        if state_seq.len() < token.chars().count() {
                             continue;
                         }
 
@@ -153,7 +355,18 @@ fn _state_scan_tokens(
 }
 
 #[pyfunction]
+#[pyo3(signature = (
+    fsm_transitions,
+    alphabet_symbol_mapping,
+    alphabet_anything_value,
+    fsm_initial,
+    fsm_finals,
+    vocabulary,
+    start_state,
+    report_mode="quiet"
+))]
 fn state_scan_tokens(
+    py: Python,
     fsm_transitions: &PyDict,
     alphabet_symbol_mapping: &PyDict,
     alphabet_anything_value: i32,
@@ -161,18 +374,13 @@ fn state_scan_tokens(
     fsm_finals: &PySet,
     vocabulary: &PyDict,
     start_state: i32,
-) -> PyResult<Vec<(i32, i32)>> {
-    let n_threads = 16;
+    report_mode: &str,
+) -> PyResult<(Vec<(i32, i32)>, Option<Py<PyDict>>)> {
+    let mut report = Report::new(ReportMode::parse(report_mode)?);
 
     let start_time = std::time::Instant::now();
     let vocabulary_map = vocabulary.extract::<BTreeMap<String, Vec<i32>>>()?;
-    let tokens = vocabulary_map.keys().cloned().collect::<Vec<String>>();
-    let _token_ids = vocabulary_map.values().cloned().collect::<Vec<Vec<i32>>>();
-
-    println!("tokens: {:?}", start_time.elapsed());
-
-    let n_tokens = tokens.len();
-    let _chunk_size = n_tokens / n_threads;
+    report.phase("extract_vocab", start_time.elapsed());
 
     let start_time = std::time::Instant::now();
     let fsm_transitions_map = fsm_transitions
@@ -183,7 +391,7 @@ fn state_scan_tokens(
             Ok((k, v))
         })
         .collect::<Result<BTreeMap<(i32, i32), i32>, PyErr>>()?;
-    println!("fsm_transitions_map: {:?}", start_time.elapsed());
+    report.phase("build_transitions", start_time.elapsed());
 
     let start_time = std::time::Instant::now();
     let alphabet_symbol_mapping_map = alphabet_symbol_mapping
@@ -194,14 +402,14 @@ fn state_scan_tokens(
             Ok((k, v))
         })
         .collect::<Result<BTreeMap<char, i32>, PyErr>>()?;
-    println!("alphabet_symbol_mapping_map: {:?}", start_time.elapsed());
+    report.phase("build_alphabet", start_time.elapsed());
 
     let start_time = std::time::Instant::now();
     let fsm_finals_set = fsm_finals
         .iter()
         .map(|v| v.extract::<i32>())
         .collect::<Result<BTreeSet<i32>, PyErr>>()?;
-    println!("fsm_finals_set: {:?}", start_time.elapsed());
+    report.phase("build_finals", start_time.elapsed());
 
     let start_time = std::time::Instant::now();
     let res = _state_scan_tokens(
@@ -212,34 +420,38 @@ fn state_scan_tokens(
         &fsm_finals_set,
         &vocabulary_map,
         start_state,
+        STANDALONE_SCAN_THREADS,
     )?;
-    println!("state_scan_tokens: {:?}", start_time.elapsed());
+    report.phase("scan", start_time.elapsed());
 
-    Ok(res)
+    Ok((res, report.into_py_dict(py)?))
 }
 
 #[pyfunction]
+#[pyo3(signature = (
+    fsm_transitions,
+    alphabet_symbol_mapping,
+    alphabet_anything_value,
+    fsm_initial,
+    fsm_finals,
+    vocabulary,
+    progress_callback=None,
+    cancellation_token=None,
+    raise_on_cancel=false
+))]
 fn create_fsm_index_end_to_end_rust(
+    py: Python,
     fsm_transitions: &PyDict,
     alphabet_symbol_mapping: &PyDict,
     alphabet_anything_value: i32,
     fsm_initial: i32,
     fsm_finals: &PySet,
     vocabulary: &PyDict,
+    progress_callback: Option<PyObject>,
+    cancellation_token: Option<Py<CancellationToken>>,
+    raise_on_cancel: bool,
 ) -> PyResult<BTreeMap<i32, BTreeSet<(i32, i32)>>> {
-    let mut states_to_token_subsets: BTreeMap<i32, BTreeMap<i32, i32>> =
-        std::collections::BTreeMap::new();
-    let mut seen: BTreeSet<i32> = std::collections::BTreeSet::new();
-    let mut next_states = vec![fsm_initial];
-
-    // TODO: consolidate type conversion
-    let n_threads = 16;
     let vocabulary_map = vocabulary.extract::<BTreeMap<String, Vec<i32>>>()?;
-    let tokens = vocabulary_map.keys().cloned().collect::<Vec<String>>();
-    let _token_ids = vocabulary_map.values().cloned().collect::<Vec<Vec<i32>>>();
-
-    let n_tokens = tokens.len();
-    let _chunk_size = n_tokens / n_threads;
 
     let fsm_transitions_map = fsm_transitions
         .iter()
@@ -265,50 +477,162 @@ fn create_fsm_index_end_to_end_rust(
         .map(|v| v.extract::<i32>())
         .collect::<Result<BTreeSet<i32>, PyErr>>()?;
 
-    // TODO: can this be parallelized? if there are more than one item in next_states
-    // maybe we can parallelize the state_scan_tokens
-    while let Some(start_state) = next_states.pop() {
-        let _start = std::time::Instant::now();
-        let token_ids_end_states = _state_scan_tokens(
-            &fsm_transitions_map,
-            &alphabet_symbol_mapping_map,
-            alphabet_anything_value,
-            fsm_initial,
-            &fsm_finals_set,
-            &vocabulary_map,
-            start_state,
-        )?;
-        for token_id_and_end_state in token_ids_end_states {
-            let end_state = token_id_and_end_state.1;
-            if !seen.contains(&end_state) {
-                next_states.push(end_state);
+    let cancelled: Arc<AtomicBool> = cancellation_token
+        .as_ref()
+        .map(|token| Arc::clone(&token.borrow(py).cancelled))
+        .unwrap_or_default();
+    let (progress_tx, progress_rx): (Sender<BuildProgress>, Receiver<BuildProgress>) = unbounded();
+
+    // The reporter thread runs with the GIL released and briefly reacquires
+    // it via `Python::with_gil` each tick to invoke the Python progress
+    // callback; the frontier BFS itself is shared with the `_compiled` and
+    // `_dense` entry points via `run_frontier_bfs`.
+    let states_to_token_subsets_btree = py.allow_threads(|| {
+        thread::scope(|s| {
+            if let Some(callback) = progress_callback.as_ref() {
+                let progress_rx = progress_rx.clone();
+                s.spawn(move || {
+                    let mut latest: Option<BuildProgress> = None;
+                    let mut last_call = std::time::Instant::now();
+                    loop {
+                        match progress_rx.recv_timeout(PROGRESS_INTERVAL) {
+                            Ok(progress) => latest = Some(progress),
+                            Err(RecvTimeoutError::Timeout) => {}
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                        if last_call.elapsed() >= PROGRESS_INTERVAL {
+                            if let Some(progress) = latest.take() {
+                                Python::with_gil(|py| {
+                                    if let Ok(dict) = progress.to_py_dict(py) {
+                                        let _ = callback.call1(py, (dict,));
+                                    }
+                                });
+                            }
+                            last_call = std::time::Instant::now();
+                        }
+                    }
+                });
             }
-            states_to_token_subsets
-                .entry(start_state)
-                .or_default()
-                .insert(token_id_and_end_state.0, token_id_and_end_state.1);
-        }
-        // println!("state_scan_tokens: {:?}", start.elapsed());
-        seen.insert(start_state);
-    }
 
-    let mut states_to_token_subsets_btree: BTreeMap<i32, BTreeSet<(i32, i32)>> =
-        std::collections::BTreeMap::new();
+            let options = FrontierOptions {
+                cancelled: Some(Arc::clone(&cancelled)),
+                progress_tx: Some(progress_tx.clone()),
+                tokens_per_state: vocabulary_map.len(),
+            };
+            let result = run_frontier_bfs(fsm_initial, options, |start_state| {
+                _state_scan_tokens(
+                    &fsm_transitions_map,
+                    &alphabet_symbol_mapping_map,
+                    alphabet_anything_value,
+                    fsm_initial,
+                    &fsm_finals_set,
+                    &vocabulary_map,
+                    start_state,
+                    FRONTIER_SCAN_THREADS,
+                )
+                .expect("state scan failed")
+            });
+            drop(progress_tx);
+            result
+        })
+    });
+
+    let was_cancelled = cancelled.load(Ordering::SeqCst);
 
-    for (k, v) in states_to_token_subsets.iter() {
-        let mut token_subsets = BTreeSet::new();
-        for (k1, v1) in v.iter() {
-            token_subsets.insert((*k1, *v1));
-        }
-        states_to_token_subsets_btree.insert(*k, token_subsets);
+    if was_cancelled && raise_on_cancel {
+        return Err(PyKeyboardInterrupt::new_err(
+            "create_fsm_index_end_to_end_rust cancelled",
+        ));
     }
 
     Ok(states_to_token_subsets_btree)
 }
 
+/// `create_fsm_index_end_to_end_rust`, but scanning a `CompiledVocabulary`
+/// (interned once, reusable across calls) instead of a `vocabulary: &PyDict`
+/// that is re-extracted on every call and whose tokens `_state_scan_tokens`
+/// re-resolves character by character for every state. Same worker-pool frontier BFS as the `PyDict`
+/// entry point, without the progress/cancellation plumbing.
+#[pyfunction]
+fn create_fsm_index_end_to_end_rust_compiled(
+    fsm_transitions: &PyDict,
+    fsm_initial: i32,
+    fsm_finals: &PySet,
+    vocabulary: &CompiledVocabulary,
+) -> PyResult<BTreeMap<i32, BTreeSet<(i32, i32)>>> {
+    let fsm_transitions_map = fsm_transitions
+        .iter()
+        .map(|(k, v)| {
+            let k = k.extract::<(i32, i32)>()?;
+            let v = v.extract::<i32>()?;
+            Ok((k, v))
+        })
+        .collect::<Result<BTreeMap<(i32, i32), i32>, PyErr>>()?;
+
+    let fsm_finals_set = fsm_finals
+        .iter()
+        .map(|v| v.extract::<i32>())
+        .collect::<Result<BTreeSet<i32>, PyErr>>()?;
+
+    let states_to_token_subsets_btree =
+        run_frontier_bfs(fsm_initial, FrontierOptions::default(), |start_state| {
+            _state_scan_tokens_compiled(
+                &fsm_transitions_map,
+                &fsm_finals_set,
+                vocabulary,
+                start_state,
+                FRONTIER_SCAN_THREADS,
+            )
+        });
+
+    Ok(states_to_token_subsets_btree)
+}
+
+/// `create_fsm_index_end_to_end_rust_compiled`, but walking a `DenseFsm`
+/// (built once up front) instead of the sparse `BTreeMap<(i32, i32), i32>`,
+/// trading the per-symbol tree descent for an O(1) array index.
+#[pyfunction]
+fn create_fsm_index_end_to_end_rust_dense(
+    fsm_transitions: &PyDict,
+    fsm_initial: i32,
+    fsm_finals: &PySet,
+    vocabulary: &CompiledVocabulary,
+) -> PyResult<BTreeMap<i32, BTreeSet<(i32, i32)>>> {
+    let fsm_transitions_map = fsm_transitions
+        .iter()
+        .map(|(k, v)| {
+            let k = k.extract::<(i32, i32)>()?;
+            let v = v.extract::<i32>()?;
+            Ok((k, v))
+        })
+        .collect::<Result<BTreeMap<(i32, i32), i32>, PyErr>>()?;
+
+    let fsm_finals_set = fsm_finals
+        .iter()
+        .map(|v| v.extract::<i32>())
+        .collect::<Result<BTreeSet<i32>, PyErr>>()?;
+
+    let dense_fsm = DenseFsm::build(&fsm_transitions_map, &fsm_finals_set);
+
+    let states_to_token_subsets_btree =
+        run_frontier_bfs(fsm_initial, FrontierOptions::default(), |start_state| {
+            _state_scan_tokens_dense(&dense_fsm, vocabulary, start_state, FRONTIER_SCAN_THREADS)
+        });
+
+    Ok(states_to_token_subsets_btree)
+}
+
 #[pymodule]
 fn rustlines(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(state_scan_tokens, m)?)?;
     m.add_function(wrap_pyfunction!(create_fsm_index_end_to_end_rust, m)?)?;
+    m.add_function(wrap_pyfunction!(create_fsm_index_end_to_end_rust_compiled, m)?)?;
+    m.add_function(wrap_pyfunction!(create_fsm_index_end_to_end_rust_dense, m)?)?;
+    m.add_function(wrap_pyfunction!(write_index, m)?)?;
+    m.add_function(wrap_pyfunction!(open_index, m)?)?;
+    m.add_class::<CancellationToken>()?;
+    m.add_class::<MmapIndex>()?;
+    m.add_class::<CompiledVocabulary>()?;
+    m.add_class::<DenseFsm>()?;
     Ok(())
 }
@@ -0,0 +1,258 @@
+//! On-disk, mmap-backed format for the `BTreeMap<i32, BTreeSet<(i32, i32)>>`
+//! produced by `create_fsm_index_end_to_end_rust`.
+//!
+//! Layout (all integers little-endian):
+//!
+//! ```text
+//! header:  magic "OLIX" (4 bytes) | version: u32 | state_count: u64
+//! directory[state_count]: state_id: i32 | byte_offset: u64 | len: u32
+//! payload: for each state, `len` pairs of (token_id: i32, end_state: i32)
+//! ```
+//!
+//! The directory is sorted by `state_id`, so `open_index` mmaps the file once
+//! and answers per-state lookups with a binary search plus a byte-slice read,
+//! without ever materializing the whole index in memory.
+
+use memmap2::Mmap;
+use pyo3::exceptions::PyIOError;
+use pyo3::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Write};
+
+const MAGIC: &[u8; 4] = b"OLIX";
+const VERSION: u32 = 1;
+const DIRECTORY_ENTRY_LEN: usize = 4 + 8 + 4;
+const PAIR_LEN: usize = 4 + 4;
+
+fn io_err(err: io::Error) -> PyErr {
+    PyIOError::new_err(err.to_string())
+}
+
+/// Serialize `index` to `path` in the on-disk format described above.
+#[pyfunction]
+pub fn write_index(path: &str, index: BTreeMap<i32, BTreeSet<(i32, i32)>>) -> PyResult<()> {
+    let state_count = index.len() as u64;
+    let header_len = MAGIC.len() + 4 + 8;
+    let directory_len = index.len() * DIRECTORY_ENTRY_LEN;
+
+    let mut directory = Vec::with_capacity(directory_len);
+    let mut payload = Vec::new();
+    let mut byte_offset = (header_len + directory_len) as u64;
+
+    for (state_id, token_subsets) in &index {
+        directory.extend_from_slice(&state_id.to_le_bytes());
+        directory.extend_from_slice(&byte_offset.to_le_bytes());
+        directory.extend_from_slice(&(token_subsets.len() as u32).to_le_bytes());
+
+        for (token_id, end_state) in token_subsets {
+            payload.extend_from_slice(&token_id.to_le_bytes());
+            payload.extend_from_slice(&end_state.to_le_bytes());
+        }
+        byte_offset += (token_subsets.len() * PAIR_LEN) as u64;
+    }
+
+    let mut file = File::create(path).map_err(io_err)?;
+    file.write_all(MAGIC).map_err(io_err)?;
+    file.write_all(&VERSION.to_le_bytes()).map_err(io_err)?;
+    file.write_all(&state_count.to_le_bytes()).map_err(io_err)?;
+    file.write_all(&directory).map_err(io_err)?;
+    file.write_all(&payload).map_err(io_err)?;
+
+    Ok(())
+}
+
+/// A compiled FSM->token index, mmapped read-only from disk.
+#[pyclass]
+pub struct MmapIndex {
+    mmap: Mmap,
+    state_count: usize,
+}
+
+impl MmapIndex {
+    fn directory_entry(&self, i: usize) -> (i32, u64, u32) {
+        let start = 16 + i * DIRECTORY_ENTRY_LEN;
+        let state_id = i32::from_le_bytes(self.mmap[start..start + 4].try_into().unwrap());
+        let offset = u64::from_le_bytes(self.mmap[start + 4..start + 12].try_into().unwrap());
+        let len = u32::from_le_bytes(self.mmap[start + 12..start + 16].try_into().unwrap());
+        (state_id, offset, len)
+    }
+
+    fn binary_search_state(&self, state: i32) -> Option<(u64, u32)> {
+        let (mut lo, mut hi) = (0usize, self.state_count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (state_id, offset, len) = self.directory_entry(mid);
+            match state_id.cmp(&state) {
+                std::cmp::Ordering::Equal => return Some((offset, len)),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+            }
+        }
+        None
+    }
+}
+
+#[pymethods]
+impl MmapIndex {
+    /// Return the `(token_id, end_state)` pairs allowed from `state`, or an
+    /// empty list if `state` is not present in the index.
+    fn get(&self, state: i32) -> Vec<(i32, i32)> {
+        let Some((offset, len)) = self.binary_search_state(state) else {
+            return Vec::new();
+        };
+        let start = offset as usize;
+        let end = start + len as usize * PAIR_LEN;
+        self.mmap[start..end]
+            .chunks_exact(PAIR_LEN)
+            .map(|chunk| {
+                let token_id = i32::from_le_bytes(chunk[0..4].try_into().unwrap());
+                let end_state = i32::from_le_bytes(chunk[4..8].try_into().unwrap());
+                (token_id, end_state)
+            })
+            .collect()
+    }
+
+    fn __len__(&self) -> usize {
+        self.state_count
+    }
+}
+
+/// Open `path` and return an `MmapIndex` that answers per-state lookups by
+/// mmap without loading the full structure into memory.
+///
+/// The header, directory, and every directory entry's payload range are
+/// validated up front so a truncated or partially-written file (e.g. a
+/// reader opening the index while a writer is still flushing, or after a
+/// crash mid-write) is rejected with a `PyIOError` here rather than causing
+/// an out-of-bounds slice panic on a later `get()`. The directory is also
+/// checked to be strictly sorted by `state_id` (the invariant
+/// `binary_search_state` relies on) so a corrupted-but-not-truncated file
+/// fails loudly here instead of making `get()` silently return wrong or no
+/// results.
+#[pyfunction]
+pub fn open_index(path: &str) -> PyResult<MmapIndex> {
+    let file = File::open(path).map_err(io_err)?;
+    let mmap = unsafe { Mmap::map(&file) }.map_err(io_err)?;
+
+    if mmap.len() < 16 || &mmap[0..4] != MAGIC {
+        return Err(PyIOError::new_err("not an outlines mmap index file"));
+    }
+    let version = u32::from_le_bytes(mmap[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(PyIOError::new_err(format!(
+            "unsupported index version {version}"
+        )));
+    }
+    let state_count = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+
+    let directory_len = state_count
+        .checked_mul(DIRECTORY_ENTRY_LEN)
+        .and_then(|len| len.checked_add(16))
+        .ok_or_else(|| PyIOError::new_err("index directory length overflows"))?;
+    if directory_len > mmap.len() {
+        return Err(PyIOError::new_err(
+            "truncated index: directory extends past end of file",
+        ));
+    }
+
+    let index = MmapIndex { mmap, state_count };
+    let mut prev_state_id: Option<i32> = None;
+    for i in 0..state_count {
+        let (state_id, offset, len) = index.directory_entry(i);
+        if let Some(prev_state_id) = prev_state_id {
+            if state_id <= prev_state_id {
+                return Err(PyIOError::new_err(
+                    "corrupt index: directory is not sorted by state_id",
+                ));
+            }
+        }
+        prev_state_id = Some(state_id);
+
+        let payload_end = (offset as usize)
+            .checked_add(len as usize * PAIR_LEN)
+            .ok_or_else(|| PyIOError::new_err("index entry length overflows"))?;
+        if offset as usize > index.mmap.len() || payload_end > index.mmap.len() {
+            return Err(PyIOError::new_err(
+                "truncated index: entry payload extends past end of file",
+            ));
+        }
+    }
+
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "rustlines_mmap_index_test_{name}_{:?}",
+            std::thread::current().id()
+        ));
+        path
+    }
+
+    fn sample_index() -> BTreeMap<i32, BTreeSet<(i32, i32)>> {
+        BTreeMap::from([
+            (0, BTreeSet::from([(1, 2), (3, 4)])),
+            (2, BTreeSet::from([(5, 6)])),
+            (7, BTreeSet::new()),
+        ])
+    }
+
+    #[test]
+    fn round_trips_through_write_and_open() {
+        let path = temp_path("round_trip");
+        let index = sample_index();
+        write_index(path.to_str().unwrap(), index.clone()).expect("write_index failed");
+
+        let opened = open_index(path.to_str().unwrap()).expect("open_index failed");
+        assert_eq!(opened.__len__(), index.len());
+        for (state, token_subsets) in &index {
+            let mut got = opened.get(*state);
+            got.sort();
+            let mut want: Vec<(i32, i32)> = token_subsets.iter().copied().collect();
+            want.sort();
+            assert_eq!(got, want);
+        }
+        assert_eq!(opened.get(999), Vec::new());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let path = temp_path("truncated");
+        write_index(path.to_str().unwrap(), sample_index()).expect("write_index failed");
+
+        let bytes = std::fs::read(&path).expect("read back written file");
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).expect("truncate file");
+
+        assert!(open_index(path.to_str().unwrap()).is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rejects_out_of_order_directory() {
+        let path = temp_path("unsorted");
+        write_index(path.to_str().unwrap(), sample_index()).expect("write_index failed");
+
+        let mut bytes = std::fs::read(&path).expect("read back written file");
+        // Swap the first two directory entries' state_id fields so the
+        // directory is no longer sorted, without touching offset/len/payload.
+        let first_state_id = 16;
+        let second_state_id = 16 + DIRECTORY_ENTRY_LEN;
+        for i in 0..4 {
+            bytes.swap(first_state_id + i, second_state_id + i);
+        }
+        std::fs::write(&path, &bytes).expect("rewrite corrupted file");
+
+        assert!(open_index(path.to_str().unwrap()).is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
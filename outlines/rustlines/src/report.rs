@@ -0,0 +1,69 @@
+//! A small `Report` abstraction (after the `quiet` / `text` / `structured`
+//! modes used by thin-provisioning-tools) standing in for the unconditional
+//! `println!` timing lines that used to ship straight to stdout. `Quiet`
+//! drops timings, `Text` logs each phase to stderr as it completes, and
+//! `Structured` accumulates them and hands them back to Python as a dict
+//! instead of printing anything, so profiling is opt-in rather than baked
+//! into every call.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReportMode {
+    Quiet,
+    Text,
+    Structured,
+}
+
+impl ReportMode {
+    pub fn parse(mode: &str) -> PyResult<Self> {
+        match mode {
+            "quiet" => Ok(ReportMode::Quiet),
+            "text" => Ok(ReportMode::Text),
+            "structured" => Ok(ReportMode::Structured),
+            other => Err(PyValueError::new_err(format!(
+                "unknown report mode {other:?}, expected \"quiet\", \"text\" or \"structured\""
+            ))),
+        }
+    }
+}
+
+/// Collects phase timings for a single call, routed according to its
+/// `ReportMode`.
+pub struct Report {
+    mode: ReportMode,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Report {
+    pub fn new(mode: ReportMode) -> Self {
+        Report {
+            mode,
+            phases: Vec::new(),
+        }
+    }
+
+    pub fn phase(&mut self, name: &'static str, elapsed: Duration) {
+        match self.mode {
+            ReportMode::Quiet => {}
+            ReportMode::Text => eprintln!("{name}: {elapsed:?}"),
+            ReportMode::Structured => self.phases.push((name, elapsed)),
+        }
+    }
+
+    /// `None` unless the mode is `Structured`, in which case it is the
+    /// `{"<phase>_us": microseconds}` dict to return alongside the result.
+    pub fn into_py_dict(self, py: Python) -> PyResult<Option<Py<PyDict>>> {
+        if self.mode != ReportMode::Structured {
+            return Ok(None);
+        }
+        let dict = PyDict::new(py);
+        for (name, elapsed) in self.phases {
+            dict.set_item(format!("{name}_us"), elapsed.as_micros())?;
+        }
+        Ok(Some(dict.into()))
+    }
+}
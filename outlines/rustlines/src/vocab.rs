@@ -0,0 +1,76 @@
+//! A vocabulary pre-interned into integer alphabet symbol-ids.
+//!
+//! `state_scan_tokens`/`create_fsm_index_end_to_end_rust` re-extract the
+//! vocabulary `PyDict` on every call and `_walk_fsm` re-resolves each `char`
+//! through `alphabet_symbol_mapping` on the hot path. `CompiledVocabulary` is
+//! built once and holds, per token, the already-resolved symbol-id sequence
+//! (falling back to `anything` at build time rather than per lookup), so the
+//! walk loop only ever indexes integers.
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::BTreeMap;
+
+/// One compiled vocabulary entry: a token's symbol-id sequence plus the
+/// token-ids that share that text (tokenizers may map several ids to the
+/// same string).
+#[derive(Clone)]
+pub struct CompiledToken {
+    pub symbols: Vec<i32>,
+    pub token_ids: Vec<i32>,
+}
+
+#[pyclass]
+#[derive(Clone)]
+pub struct CompiledVocabulary {
+    pub tokens: Vec<CompiledToken>,
+    anything_value: i32,
+    symbol_mapping: BTreeMap<char, i32>,
+}
+
+#[pymethods]
+impl CompiledVocabulary {
+    #[new]
+    fn new(
+        vocabulary: &PyDict,
+        alphabet_symbol_mapping: &PyDict,
+        alphabet_anything_value: i32,
+    ) -> PyResult<Self> {
+        let symbol_mapping = alphabet_symbol_mapping
+            .iter()
+            .map(|(k, v)| Ok((k.extract::<char>()?, v.extract::<i32>()?)))
+            .collect::<PyResult<BTreeMap<char, i32>>>()?;
+
+        let mut compiled = CompiledVocabulary {
+            tokens: Vec::with_capacity(vocabulary.len()),
+            anything_value: alphabet_anything_value,
+            symbol_mapping,
+        };
+        compiled.extend_from_dict(vocabulary)?;
+        Ok(compiled)
+    }
+
+    /// Intern and append more tokens without rebuilding the existing ones.
+    fn add_tokens(&mut self, vocabulary: &PyDict) -> PyResult<()> {
+        self.extend_from_dict(vocabulary)
+    }
+
+    fn __len__(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+impl CompiledVocabulary {
+    fn extend_from_dict(&mut self, vocabulary: &PyDict) -> PyResult<()> {
+        for (text, token_ids) in vocabulary.iter() {
+            let text = text.extract::<String>()?;
+            let token_ids = token_ids.extract::<Vec<i32>>()?;
+            let symbols = text
+                .chars()
+                .map(|c| *self.symbol_mapping.get(&c).unwrap_or(&self.anything_value))
+                .collect::<Vec<i32>>();
+            self.tokens.push(CompiledToken { symbols, token_ids });
+        }
+        Ok(())
+    }
+}